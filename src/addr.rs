@@ -1,9 +1,9 @@
-use std::{fmt::Display, ops::{Add, BitAnd}};
+use core::{fmt::Display, ops::{Add, BitAnd}};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PhysAddr(u64);
 impl Display for PhysAddr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "P0x{:x}", self.0)
     }
 }
@@ -28,6 +28,18 @@ impl PhysAddr {
     pub const fn bits(self) -> u64 {
         self.0
     }
+    /// Overlays the low `width` bits of `self` with `low_bits`'s, for
+    /// reconstructing a superpage's physical address from its PTE frame.
+    pub const fn with_low_bits(self, low_bits: u64, width: u32) -> Self {
+        let mask = (1u64 << width) - 1;
+        Self((self.0 & !mask) | (low_bits & mask))
+    }
+    /// Whether any of the low `width` bits are set (RISC-V misaligned
+    /// superpage check).
+    pub const fn has_low_bits(self, width: u32) -> bool {
+        let mask = (1u64 << width) - 1;
+        self.0 & mask != 0
+    }
 }
 
 impl BitAnd<u64> for PhysAddr {
@@ -39,10 +51,65 @@ impl BitAnd<u64> for PhysAddr {
     }
 }
 
-#[derive(Copy, Clone)]
+/// RISC-V paging mode (`satp.MODE`), selecting walk depth and VPN field width.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    /// No translation: `satp.MODE = 0`, virtual and physical addresses match.
+    Bare,
+    /// 2-level, 10-bit VPN fields, 32-bit virtual / 34-bit physical addresses.
+    Sv32,
+    /// 3-level, 9-bit VPN fields, 39-bit virtual addresses.
+    Sv39,
+    /// 4-level, 9-bit VPN fields, 48-bit virtual addresses.
+    Sv48,
+    /// 5-level, 9-bit VPN fields, 57-bit virtual addresses.
+    Sv57,
+}
+
+impl PagingMode {
+    /// Number of page-table levels walked, root first (0 for `Bare`).
+    pub const fn levels(self) -> usize {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 => 2,
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+    /// Width in bits of a single VPN field.
+    pub const fn vpn_bits(self) -> u32 {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 => 10,
+            PagingMode::Sv39 | PagingMode::Sv48 | PagingMode::Sv57 => 9,
+        }
+    }
+    /// Width in bits of the physical page number a RISC-V leaf PTE holds
+    /// under this mode. `PageTableEntry` does not use it: it stores a 40-bit
+    /// frame number (address bits 12..51) in every mode.
+    pub const fn frame_bits(self) -> u32 {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 => 22,
+            PagingMode::Sv39 | PagingMode::Sv48 | PagingMode::Sv57 => 44,
+        }
+    }
+    /// Size in bits of the region covered by a leaf found at `level` (1 =
+    /// root); a leaf found before the deepest level is a superpage.
+    pub const fn region_width(self, level: usize) -> u32 {
+        12 + self.vpn_bits() * (self.levels() - level) as u32
+    }
+    /// Number of entries in one page table under this mode (`2^vpn_bits`).
+    pub const fn table_entries(self) -> usize {
+        1usize << self.vpn_bits()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct VirtAddr(u64);
 impl Display for VirtAddr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "V0x{:x}", self.0)
     }
 }
@@ -60,23 +127,22 @@ impl VirtAddr {
     pub const fn from_bits(bits: u64) -> Self {
         Self(bits)
     }
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
     pub const fn page_offset(self) -> u64 {
         self.0 & 4095
     }
     pub const fn virtual_page_number(self) -> u64 {
         self.0 >> 12
     }
-    pub const fn vpn1(self) -> usize {
-        ((self.0 >> 39) & 511) as usize
-    }
-    pub const fn vpn2(self) -> usize {
-        ((self.0 >> 30) & 511) as usize
-    }
-    pub const fn vpn3(self) -> usize {
-        ((self.0 >> 21) & 511) as usize
-    }
-    pub const fn vpn4(self) -> usize {
-        ((self.0 >> 12) & 511) as usize
+    /// Extracts the VPN field for `level` (1 = root) under `mode`.
+    pub const fn vpn(self, level: usize, mode: PagingMode) -> usize {
+        let bits = mode.vpn_bits();
+        let levels = mode.levels();
+        let shift = 12 + bits * (levels - level) as u32;
+        let mask = (1u64 << bits) - 1;
+        ((self.0 >> shift) & mask) as usize
     }
 }
 
@@ -0,0 +1,443 @@
+//! Configurable set-associative caches, shared by the TLB and the L1 data
+//! cache, with a pluggable eviction policy.
+
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::stat::CacheStats;
+
+/// Eviction policy applied once every way in a set is valid. Selectable on
+/// `Machine` so a single access trace can be compared across policies.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReplacementPolicy {
+    /// Evict the least-recently-used way (recency bumped on every hit).
+    Lru,
+    /// Evict the way that has been resident the longest, ignoring hits.
+    Fifo,
+    /// Evict a uniformly random way.
+    Random,
+}
+
+/// Minimal xorshift64 PRNG backing `ReplacementPolicy::Random` (no `rand`
+/// dependency).
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+/// Picks which way to evict, given each way's `(valid, recency, inserted)`.
+/// An invalid way is always preferred over consulting `policy`.
+fn choose_victim(ways: &[(bool, u64, u64)], policy: ReplacementPolicy, rng: &mut Rng) -> usize {
+    if let Some(i) = ways.iter().position(|(valid, ..)| !valid) {
+        return i;
+    }
+
+    match policy {
+        ReplacementPolicy::Lru => ways
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (_, recency, _))| *recency)
+            .map(|(i, _)| i)
+            .expect("associativity is always > 0"),
+        ReplacementPolicy::Fifo => ways
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (_, _, inserted))| *inserted)
+            .map(|(i, _)| i)
+            .expect("associativity is always > 0"),
+        ReplacementPolicy::Random => (rng.next() as usize) % ways.len(),
+    }
+}
+
+struct Way<V> {
+    tag: u64,
+    recency: u64,
+    /// Clock value at insertion, never touched by a hit; backs `Fifo`.
+    inserted: u64,
+    value: Option<V>,
+}
+
+impl<V> Way<V> {
+    fn empty() -> Self {
+        Self {
+            tag: 0,
+            recency: 0,
+            inserted: 0,
+            value: None,
+        }
+    }
+}
+
+/// A set-associative store keyed by an arbitrary `u64`; eviction policy is
+/// chosen per-call via `choose_victim`.
+struct SetAssocCache<V> {
+    sets: Vec<Vec<Way<V>>>,
+    clock: u64,
+}
+
+impl<V: Clone> SetAssocCache<V> {
+    fn new(num_sets: usize, associativity: usize) -> Self {
+        Self {
+            sets: (0..num_sets)
+                .map(|_| (0..associativity).map(|_| Way::empty()).collect())
+                .collect(),
+            clock: 0,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn index_of(&self, key: u64) -> usize {
+        (key % self.sets.len() as u64) as usize
+    }
+    fn tag_of(&self, key: u64) -> u64 {
+        key / self.sets.len() as u64
+    }
+
+    /// Looks up `key`, returning a copy of the cached value on a hit and
+    /// updating `stats` and the way's recency.
+    fn lookup(&mut self, key: u64, stats: &mut CacheStats) -> Option<V> {
+        let index = self.index_of(key);
+        let tag = self.tag_of(key);
+        let clock = self.tick();
+        let set = &mut self.sets[index];
+
+        for way in set.iter_mut() {
+            if way.value.is_some() && way.tag == tag {
+                way.recency = clock;
+                stats.hit();
+                return way.value.clone();
+            }
+        }
+
+        stats.miss();
+        None
+    }
+
+    /// Applies `f` to the cached value for `key` if present, bumping its
+    /// recency. Returns whether `key` was present (a hit).
+    fn update(&mut self, key: u64, f: impl FnOnce(&mut V)) -> bool {
+        let index = self.index_of(key);
+        let tag = self.tag_of(key);
+        let clock = self.tick();
+        let set = &mut self.sets[index];
+
+        for way in set.iter_mut() {
+            if let Some(value) = way.value.as_mut() {
+                if way.tag == tag {
+                    way.recency = clock;
+                    f(value);
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Inserts `value` for `key`, evicting a victim chosen by `policy`.
+    /// Returns the evicted `(key, value)` pair, if any.
+    fn insert(
+        &mut self,
+        key: u64,
+        value: V,
+        policy: ReplacementPolicy,
+        rng: &mut Rng,
+    ) -> Option<(u64, V)> {
+        let index = self.index_of(key);
+        let tag = self.tag_of(key);
+        let clock = self.tick();
+        let num_sets = self.sets.len() as u64;
+        let set = &mut self.sets[index];
+
+        let meta: Vec<(bool, u64, u64)> = set
+            .iter()
+            .map(|w| (w.value.is_some(), w.recency, w.inserted))
+            .collect();
+        let victim = choose_victim(&meta, policy, rng);
+
+        let evicted = set[victim]
+            .value
+            .take()
+            .map(|v| (set[victim].tag * num_sets + index as u64, v));
+
+        set[victim] = Way {
+            tag,
+            recency: clock,
+            inserted: clock,
+            value: Some(value),
+        };
+
+        evicted
+    }
+
+    /// All valid `(key, value)` pairs currently cached, for inspection/display.
+    fn entries(&self) -> Vec<(u64, V)> {
+        let num_sets = self.sets.len() as u64;
+        let mut out = Vec::new();
+        for (index, set) in self.sets.iter().enumerate() {
+            for way in set {
+                if let Some(value) = &way.value {
+                    out.push((way.tag * num_sets + index as u64, value.clone()));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[derive(Copy, Clone)]
+struct TlbWay {
+    tag: u64,
+    asid: u16,
+    global: bool,
+    recency: u64,
+    /// Clock value at insertion, never touched by a hit; backs `Fifo`.
+    inserted: u64,
+    frame: Option<PhysAddr>,
+}
+
+impl TlbWay {
+    fn empty() -> Self {
+        Self {
+            tag: 0,
+            asid: 0,
+            global: false,
+            recency: 0,
+            inserted: 0,
+            frame: None,
+        }
+    }
+}
+
+/// A set-associative TLB mapping virtual page numbers to physical frames,
+/// tagged by ASID so entries from different address spaces can coexist
+/// without a full flush on a context switch (RISC-V `satp.ASID` / G bit).
+pub struct Tlb {
+    sets: Vec<Vec<TlbWay>>,
+    clock: u64,
+    current_asid: u16,
+}
+
+impl Tlb {
+    pub fn new(sets: usize, associativity: usize) -> Self {
+        Self {
+            sets: (0..sets)
+                .map(|_| (0..associativity).map(|_| TlbWay::empty()).collect())
+                .collect(),
+            clock: 0,
+            current_asid: 0,
+        }
+    }
+
+    fn index_of(&self, vpn: u64) -> usize {
+        (vpn % self.sets.len() as u64) as usize
+    }
+    fn tag_of(&self, vpn: u64) -> u64 {
+        vpn / self.sets.len() as u64
+    }
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Switches the ASID used by subsequent lookups/insertions, modeling a
+    /// write to `satp` on a context switch.
+    pub fn switch_asid(&mut self, asid: u16) {
+        self.current_asid = asid;
+    }
+
+    /// Looks up `vpn` under the current ASID. A way hits if its tag matches
+    /// and either it's global or its ASID matches; a same-tag way under a
+    /// different ASID is an ASID-conflict miss rather than a cold one.
+    pub fn lookup(&mut self, vpn: u64, stats: &mut CacheStats) -> Option<PhysAddr> {
+        let index = self.index_of(vpn);
+        let tag = self.tag_of(vpn);
+        let asid = self.current_asid;
+        let clock = self.tick();
+        let set = &mut self.sets[index];
+
+        let mut tag_conflict = false;
+        for way in set.iter_mut() {
+            if way.frame.is_some() && way.tag == tag {
+                if way.global || way.asid == asid {
+                    way.recency = clock;
+                    stats.hit();
+                    return way.frame;
+                }
+                tag_conflict = true;
+            }
+        }
+
+        if tag_conflict {
+            stats.miss_asid_conflict();
+        } else {
+            stats.miss_cold();
+        }
+        None
+    }
+
+    /// Inserts the frame mapped to `vpn` under the current ASID (`global`
+    /// should mirror the leaf PTE's G bit), evicting a victim chosen by
+    /// `policy`. Returns the evicted `(vpn, frame)` pair, if any.
+    pub fn insert(
+        &mut self,
+        vpn: u64,
+        frame: PhysAddr,
+        global: bool,
+        policy: ReplacementPolicy,
+        rng: &mut Rng,
+    ) -> Option<(u64, PhysAddr)> {
+        let index = self.index_of(vpn);
+        let tag = self.tag_of(vpn);
+        let asid = self.current_asid;
+        let clock = self.tick();
+        let num_sets = self.sets.len() as u64;
+        let set = &mut self.sets[index];
+
+        let meta: Vec<(bool, u64, u64)> = set
+            .iter()
+            .map(|w| (w.frame.is_some(), w.recency, w.inserted))
+            .collect();
+        let victim = choose_victim(&meta, policy, rng);
+
+        let evicted = set[victim]
+            .frame
+            .take()
+            .map(|f| (set[victim].tag * num_sets + index as u64, f));
+
+        set[victim] = TlbWay {
+            tag,
+            asid,
+            global,
+            recency: clock,
+            inserted: clock,
+            frame: Some(frame),
+        };
+
+        evicted
+    }
+
+    /// Invalidates entries tagged with `asid` (mirroring `SFENCE.VMA`),
+    /// ignoring global entries. Invalidates just `addr`'s page if given,
+    /// otherwise every page under `asid`.
+    pub fn flush(&mut self, asid: u16, addr: Option<VirtAddr>) {
+        match addr {
+            Some(virt_addr) => {
+                let vpn = virt_addr.virtual_page_number();
+                let index = self.index_of(vpn);
+                let tag = self.tag_of(vpn);
+                for way in self.sets[index].iter_mut() {
+                    if way.tag == tag && way.asid == asid && !way.global {
+                        way.frame = None;
+                    }
+                }
+            }
+            None => {
+                for set in self.sets.iter_mut() {
+                    for way in set.iter_mut() {
+                        if way.asid == asid && !way.global {
+                            way.frame = None;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// All valid `(vpn, frame, asid, global)` entries, for display.
+    pub fn entries(&self) -> Vec<(u64, PhysAddr, u16, bool)> {
+        let num_sets = self.sets.len() as u64;
+        let mut out = Vec::new();
+        for (index, set) in self.sets.iter().enumerate() {
+            for way in set {
+                if let Some(frame) = way.frame {
+                    out.push((way.tag * num_sets + index as u64, frame, way.asid, way.global));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// A cached block of memory, write-back: `dirty` tracks whether it holds
+/// writes that haven't been flushed to `Memory` yet.
+#[derive(Clone)]
+pub struct CacheLine {
+    pub data: Vec<u8>,
+    pub dirty: bool,
+}
+
+/// A set-associative, write-back/write-allocate L1 data cache, indexed by
+/// physical line number.
+pub struct L1Cache {
+    cache: SetAssocCache<CacheLine>,
+    line_size: usize,
+}
+
+impl L1Cache {
+    pub fn new(sets: usize, associativity: usize, line_size: usize) -> Self {
+        Self {
+            cache: SetAssocCache::new(sets, associativity),
+            line_size,
+        }
+    }
+
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    fn line_number(&self, addr: PhysAddr) -> u64 {
+        addr.bits() / self.line_size as u64
+    }
+
+    pub fn lookup(&mut self, addr: PhysAddr, stats: &mut CacheStats) -> Option<CacheLine> {
+        self.cache.lookup(self.line_number(addr), stats)
+    }
+
+    /// Writes `value` into the cached line containing `addr` and marks it
+    /// dirty. Returns whether it was a hit, bumping the matching counter.
+    pub fn write(&mut self, addr: PhysAddr, value: u8, stats: &mut CacheStats) -> bool {
+        let offset = (addr.bits() % self.line_size as u64) as usize;
+        let hit = self.cache.update(self.line_number(addr), |line| {
+            line.data[offset] = value;
+            line.dirty = true;
+        });
+        if hit {
+            stats.write_hit();
+        } else {
+            stats.write_miss();
+        }
+        hit
+    }
+
+    /// Inserts `line` (the block containing `addr`), evicting a victim
+    /// chosen by `policy`. Returns the evicted `(line_number, line)` pair.
+    pub fn insert(
+        &mut self,
+        addr: PhysAddr,
+        line: CacheLine,
+        policy: ReplacementPolicy,
+        rng: &mut Rng,
+    ) -> Option<(u64, CacheLine)> {
+        self.cache.insert(self.line_number(addr), line, policy, rng)
+    }
+
+    /// All valid `(line_number, line)` entries, for display.
+    pub fn entries(&self) -> Vec<(u64, CacheLine)> {
+        self.cache.entries()
+    }
+}
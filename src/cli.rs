@@ -1,3 +1,9 @@
+//! Host-only pretty-printing: renders a titled box around some content.
+//! Needs `String`/`Vec` and stdout, so it's only available with the `std`
+//! feature — embedders trace through their own `Trace` sink instead (see
+//! `log`).
+#![cfg(feature = "std")]
+
 use std::fmt::Display;
 
 pub fn print_box(title: &str, content: impl Display) {
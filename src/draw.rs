@@ -1,9 +1,10 @@
 //! Module for the boring formatting stuff of all the components
 
 use crate::{
-    addr::{PhysAddr, VirtAddr},
-    page::PageTable,
-    L1dCache, Machine, Tlb,
+    addr::{PagingMode, PhysAddr, VirtAddr},
+    cache::{L1Cache, Tlb},
+    page::PageTableEntry,
+    Machine,
 };
 
 pub trait Draw {
@@ -12,56 +13,39 @@ pub trait Draw {
 
 impl Draw for Tlb {
     fn draw(&self) -> String {
-        let mut empty = true;
-        let mut buf = String::new();
-
-        for (i, set) in self.sets.iter().enumerate() {
-            if set.iter().any(|e| e.valid) {
-                let mut first = true;
-                for entry in set.iter() {
-                    if entry.valid {
-                        empty = false;
-                        if !first {
-                            buf += " ";
-                        }
-                        first = false;
-
-                        let virt = (entry.tag | i as u64) << 12;
-
-                        buf += &format!(
-                            "[{} -> {}, {}]",
-                            VirtAddr::from_bits(virt),
-                            entry.addr,
-                            entry.access
-                        );
-                    }
-                }
-                buf += "\n";
-            }
+        let mut entries = self.entries();
+        if entries.is_empty() {
+            return "(empty)".to_string();
         }
 
-        if empty {
-            buf += "(empty)";
-        }
+        entries.sort_by_key(|(vpn, ..)| *vpn);
 
+        let mut buf = String::new();
+        for (vpn, frame, asid, global) in entries {
+            let g = if global { ", G" } else { "" };
+            buf += &format!(
+                "[{} -> {}, asid={asid}{g}]\n",
+                VirtAddr::from_bits(vpn << 12),
+                frame
+            );
+        }
         buf
     }
 }
 
-impl Draw for L1dCache {
+impl Draw for L1Cache {
     fn draw(&self) -> String {
+        let mut entries = self.entries();
+        if entries.is_empty() {
+            return String::new();
+        }
+
+        entries.sort_by_key(|(line, _)| *line);
+
         let mut buf = String::new();
-        for i in 0..64 {
-            if self.entries[i].has_entry() {
-                buf += &format!("{i:02}:");
-                for e in self.entries[i].entries.iter() {
-                    if e.valid {
-                        let phys = PhysAddr::from_frame_offset(e.tag, (i as u64) << 6);
-                        buf += &format!(" {phys}");
-                    }
-                }
-                buf += "\n";
-            }
+        for (line, _) in entries {
+            let phys = PhysAddr::from_bits(line * self.line_size() as u64);
+            buf += &format!("{phys}\n");
         }
         buf
     }
@@ -73,42 +57,49 @@ pub trait MachineDraw {
 
 impl MachineDraw for Machine {
     fn draw_page_map(&self) -> String {
-        fn inner(
-            m: &Machine,
-            buf: &mut String,
-            depth: i32,
-            phys_base: PhysAddr,
-            virt_base: VirtAddr,
-        ) {
-            let table = m.memory.read::<PageTable>(phys_base);
-
-            let indent = " | ".repeat(depth as usize - 1);
-            let stride = 4096 << (9 * (4 - depth));
-
-            for (i, entry) in table.iter().enumerate() {
+        if self.mode == PagingMode::Bare {
+            return "(bare mode, no page table)".to_string();
+        }
+
+        fn inner(m: &Machine, buf: &mut String, level: usize, phys_base: PhysAddr, virt_base: VirtAddr) {
+            let levels = m.mode.levels();
+
+            let indent = " | ".repeat(level - 1);
+            let stride = 1u64 << m.mode.region_width(level);
+
+            for i in 0..m.mode.table_entries() {
+                let entry = m.memory.read::<PageTableEntry>(PageTableEntry::addr_in_table(phys_base, i));
                 if !entry.is_present() {
                     continue;
                 }
 
                 let virt = virt_base + stride * i as u64;
                 let phys = entry.phys_addr();
-                if depth == 4 {
-                    *buf += &(format!("{indent}{i:03}: {virt} -> {phys}\n"));
+                // Mirrors `Machine::translate`'s own terminal check: a leaf
+                // found before the deepest level is a huge page.
+                if level == levels || entry.is_leaf() {
+                    let mmio = if m.memory.is_cacheable(phys) { "" } else { ", MMIO" };
+                    *buf += &(format!("{indent}{i:03}: {virt} -> {phys} ({stride}B page{mmio})\n"));
                 } else {
                     let x = num_mapped_entries(m, phys);
                     *buf += &format!("{indent}{i:03}: [{x} mapped entries]\n");
-                    inner(m, buf, depth + 1, phys, virt);
+                    inner(m, buf, level + 1, phys, virt);
                 }
             }
         }
 
         fn num_mapped_entries(m: &Machine, table_location: PhysAddr) -> usize {
-            let table = m.memory.read::<PageTable>(table_location);
-            table.iter().filter(|e| e.is_present()).count()
+            (0..m.mode.table_entries())
+                .filter(|&i| {
+                    m.memory
+                        .read::<PageTableEntry>(PageTableEntry::addr_in_table(table_location, i))
+                        .is_present()
+                })
+                .count()
         }
 
         let mut buf = String::new();
-        inner(&self, &mut buf, 1, self.cr3, VirtAddr::from_bits(0));
+        inner(self, &mut buf, 1, self.cr3, VirtAddr::from_bits(0));
         buf
     }
 }
@@ -1,17 +1,33 @@
-pub struct Log {
-    enable: bool,
+//! Trace sink abstraction: depth-tracked logging decoupled from any concrete
+//! I/O, so the translation core can run either as a host-side simulator
+//! (stdout) or linked into a real kernel with its own UART/serial sink.
+
+/// A sink that receives one already-formatted trace line at a time. `depth`
+/// is passed alongside so a sink can render its own indentation without the
+/// core needing to know how (or whether) that sink indents.
+pub trait Trace {
+    fn log(&mut self, depth: usize, args: core::fmt::Arguments);
+}
+
+/// Wraps a `Trace` sink with the `begin_context`/`end_context` depth
+/// tracking the translation core uses to nest related trace lines.
+pub struct Tracer<T: Trace> {
+    sink: T,
     depth: usize,
+    enable: bool,
 }
-impl Log {
-    pub fn new() -> Self {
+
+impl<T: Trace> Tracer<T> {
+    pub fn new(sink: T) -> Self {
         Self {
-            enable: true,
+            sink,
             depth: 0,
+            enable: true,
         }
     }
-    pub fn log(&self, msg: impl ToString) {
+    pub fn log(&mut self, msg: impl core::fmt::Display) {
         if self.enable {
-            println!("{}{}", "  ".repeat(self.depth), msg.to_string());
+            self.sink.log(self.depth, format_args!("{}", msg));
         }
     }
     pub fn begin_context(&mut self) {
@@ -21,3 +37,18 @@ impl Log {
         self.depth -= 1;
     }
 }
+
+/// Prints indented trace lines to stdout; the default sink for the host-side simulator.
+#[cfg(feature = "std")]
+pub struct StdoutTrace;
+
+#[cfg(feature = "std")]
+impl Trace for StdoutTrace {
+    fn log(&mut self, depth: usize, args: core::fmt::Arguments) {
+        println!("{}{}", "  ".repeat(depth), args);
+    }
+}
+
+/// The host-side simulator's tracer: indentation plus a stdout sink.
+#[cfg(feature = "std")]
+pub type Log = Tracer<StdoutTrace>;
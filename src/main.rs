@@ -1,525 +1,267 @@
-use std::{
-    fmt::{format, write, Display},
-    ops::{Add, Deref, DerefMut, Index, IndexMut},
-};
+//! Host-side simulator binary. `addr`, `page` and `stat` don't reach for
+//! `std`/`alloc` themselves, so the translation core they make up stays
+//! portable in principle -- though this crate has no `no_std` build (no
+//! `Cargo.toml`/`lib` split) to actually prove that. `log` exposes that
+//! core's tracing through a pluggable `Trace` sink instead of hard-coding
+//! stdout, and `cli`'s box-drawing stays behind the `std` feature.
+
+mod addr;
+mod cache;
+mod cli;
+mod draw;
+mod log;
+mod memory;
+mod page;
+mod stat;
+
+use addr::{PagingMode, PhysAddr, VirtAddr};
+use cache::{CacheLine, L1Cache, ReplacementPolicy, Rng, Tlb};
+use cli::print_box;
+use draw::{Draw, MachineDraw};
+use log::{Log, StdoutTrace};
+use memory::{HandlePageFault, Memory, MmioRegion, PageFaultResolution, Perm};
+use page::{AccessKind, Fault, FaultCause, PageTableEntry, PrivilegeLevel, PteFlags};
+use stat::Stats;
 
-#[derive(Copy, Clone)]
-pub struct PhysAddr(u64);
-impl Display for PhysAddr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "P0x{:x}", self.0)
-    }
-}
-impl PhysAddr {
-    pub fn from_frame_offset(frame: u64, offset: u64) -> Self {
-        Self((frame << 12) | offset)
-    }
-    pub fn with_offset(mut self, offset: u64) -> Self {
-        self.0 &= !4095;
-        self.0 |= offset & 4095;
-        self
-    }
-    pub const fn frame_offset(self) -> usize {
-        self.0 as usize & 4095
-    }
-    pub const fn frame_number(self) -> u64 {
-        self.0 >> 12
-    }
-}
-
-#[derive(Copy, Clone)]
-pub struct VirtAddr(u64);
-impl Display for VirtAddr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "V0x{:x}", self.0)
-    }
-}
-
-impl Add<u64> for VirtAddr {
-    type Output = VirtAddr;
-
-    fn add(mut self, rhs: u64) -> Self::Output {
-        self.0 += rhs;
-        self
-    }
-}
-
-impl VirtAddr {
-    pub const fn page_offset(self) -> u64 {
-        self.0 & 4095
-    }
-    pub const fn virtual_page_number(self) -> u64 {
-        self.0 >> 12
-    }
-    pub const fn vpn1(self) -> usize {
-        ((self.0 >> 39) & 511) as usize
-    }
-    pub const fn vpn2(self) -> usize {
-        ((self.0 >> 30) & 511) as usize
-    }
-    pub const fn vpn3(self) -> usize {
-        ((self.0 >> 21) & 511) as usize
-    }
-    pub const fn vpn4(self) -> usize {
-        ((self.0 >> 12) & 511) as usize
-    }
-}
-
-#[derive(Copy, Clone)]
-pub struct PageTableEntry {
-    bits: u64,
+pub struct Machine {
+    pub(crate) cr3: PhysAddr,
+    pub(crate) mode: PagingMode,
+    privilege: PrivilegeLevel,
+    tlb: Tlb,
+    pub(crate) memory: Memory,
+    cache: L1Cache,
+    /// Eviction policy shared by the TLB and the L1 cache.
+    policy: ReplacementPolicy,
+    rng: Rng,
+    stats: Stats,
+    log: Log,
 }
 
-impl PageTableEntry {
-    pub const fn new_present(target: PhysAddr) -> Self {
-        Self {
-            bits: target.0 & 0x000FFFFFFFFFFFF000 | 1,
+impl Machine {
+    pub fn translate(
+        &mut self,
+        virt_addr: VirtAddr,
+        access: AccessKind,
+        privilege: PrivilegeLevel,
+    ) -> Result<PhysAddr, Fault> {
+        if self.mode == PagingMode::Bare {
+            self.log.log("Bare mode: identity mapping, no walk");
+            return Ok(PhysAddr::from_bits(virt_addr.bits()));
         }
-    }
-
-    pub const fn new_unmapped() -> Self {
-        Self { bits: 0 }
-    }
-
-    pub const fn is_present(self) -> bool {
-        self.bits & 1 > 0
-    }
-
-    pub const fn phys_addr(self) -> PhysAddr {
-        PhysAddr(self.bits & 0x000FFFFFFFFFFFF000)
-    }
-}
-
-#[derive(Copy, Clone)]
-pub struct PageTable {
-    table: [PageTableEntry; 512],
-}
-impl Deref for PageTable {
-    type Target = [PageTableEntry; 512];
-    fn deref(&self) -> &Self::Target {
-        &self.table
-    }
-}
-impl DerefMut for PageTable {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.table
-    }
-}
 
-#[derive(Copy, Clone)]
-pub struct L1dCacheEntry {
-    valid: bool,
-    tag: u64,
-    line: [u8; 64],
-}
+        let vpn = virt_addr.virtual_page_number();
+        let page_offset = virt_addr.page_offset();
 
-#[derive(Copy, Clone)]
-pub struct L1dCacheSet {
-    entries: [L1dCacheEntry; 8],
-}
+        if let Some(frame) = self.tlb.lookup(vpn, &mut self.stats.tlb) {
+            self.log.log("TLB Hit");
+            return Ok(frame.with_offset(page_offset));
+        }
 
-impl L1dCacheSet {
-    fn has_entry(&self) -> bool {
-        self.entries.iter().any(|e| e.valid)
-    }
-}
+        self.log.log("TLB Miss");
+        self.log.log(format!(
+            "Walking {:?} ({} levels)",
+            self.mode,
+            self.mode.levels()
+        ));
 
-impl Index<usize> for L1dCacheSet {
-    type Output = L1dCacheEntry;
+        self.stats.page_faults += 1;
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.entries[index]
-    }
-}
-impl IndexMut<usize> for L1dCacheSet {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.entries[index]
-    }
-}
+        let levels = self.mode.levels();
+        let mut table_addr = self.cr3;
+        let mut phys_addr = PhysAddr::from_bits(0);
+        let mut global = false;
+        'walk: for level in 1..=levels {
+            let vpn = virt_addr.vpn(level, self.mode);
+            let entry_addr = PageTableEntry::addr_in_table(table_addr, vpn);
+            let pte = match self.memory.try_read::<PageTableEntry>(entry_addr) {
+                Ok(pte) => pte,
+                Err(_) => {
+                    let cause = FaultCause::OutOfBounds;
+                    self.log
+                        .log(format!("Page fault at level {level}: {cause:?}"));
+                    return Err(Fault {
+                        addr: virt_addr,
+                        level,
+                        cause,
+                    });
+                }
+            };
+            // A leaf found before the deepest level is a superpage (megapage/
+            // gigapage/...); a leaf at the deepest level is an ordinary page.
+            let terminal = level == levels || pte.is_leaf();
+
+            if let Some(cause) = pte.check_access(access, privilege, terminal) {
+                self.log
+                    .log(format!("Page fault at level {level}: {cause:?}"));
+                return Err(Fault {
+                    addr: virt_addr,
+                    level,
+                    cause,
+                });
+            }
 
-pub struct L1dCache {
-    entries: [L1dCacheSet; 64],
-}
+            if terminal {
+                let region_width = self.mode.region_width(level);
+                if pte.phys_addr().has_low_bits(region_width) {
+                    let cause = FaultCause::MisalignedSuperpage;
+                    self.log
+                        .log(format!("Page fault at level {level}: {cause:?}"));
+                    return Err(Fault {
+                        addr: virt_addr,
+                        level,
+                        cause,
+                    });
+                }
 
-impl L1dCacheEntry {
-    pub const fn empty() -> Self {
-        Self {
-            valid: false,
-            tag: 0,
-            line: [0; 64],
-        }
-    }
-}
-impl L1dCacheSet {
-    pub const fn empty() -> Self {
-        Self {
-            entries: [L1dCacheEntry::empty(); 8],
-        }
-    }
-}
-impl L1dCache {
-    pub const fn empty() -> Self {
-        Self {
-            entries: [L1dCacheSet::empty(); 64],
-        }
-    }
-    pub fn display(&self) -> String {
-        let mut buf = String::new();
-        for i in 0..64 {
-            if self.entries[i].has_entry() {
-                buf += &format!("{i:02}:");
-                for e in self.entries[i].entries.iter() {
-                    if e.valid {
-                        let phys = PhysAddr::from_frame_offset(e.tag, (i as u64) << 6);
-                        buf += &format!(" {phys}");
-                    }
+                let entry = self.memory.edit::<PageTableEntry>(entry_addr);
+                entry.set_accessed();
+                if access == AccessKind::Store {
+                    entry.set_dirty();
                 }
-                buf += "\n";
+                global = entry.is_global();
+                phys_addr = entry
+                    .phys_addr()
+                    .with_low_bits(virt_addr.bits(), region_width);
+
+                self.log.log(format!(
+                    "Leaf at level {level}: {}B page",
+                    1u64 << region_width
+                ));
+                break 'walk;
+            } else {
+                table_addr = pte.phys_addr();
             }
         }
-        buf
-    }
-}
 
-pub struct Memory {
-    memory: Vec<u8>,
-}
-
-impl Memory {
-    fn megabytes(mb: usize) -> Self {
-        Self {
-            memory: vec![0; mb << 20],
-        }
-    }
-    fn read<T: Copy>(&self, addr: PhysAddr) -> T {
-        let a = addr.0 as usize;
-        let len = core::mem::size_of::<T>();
+        self.stats.page_faults -= 1;
 
-        if a + len > self.memory.len() {
-            panic!("memory access out of bounds...");
+        if let Some((evicted_vpn, _)) =
+            self.tlb
+                .insert(vpn, phys_addr, global, self.policy, &mut self.rng)
+        {
+            let evicted = VirtAddr::from_bits(evicted_vpn << 12);
+            self.log.log(format!("Evicting TLB Entry {evicted}"));
+            self.stats.tlb.evict();
         }
 
-        let addr = (&self.memory[a]) as *const u8 as *const T;
+        self.log.log(format!("New TLB Entry: {virt_addr}"));
 
-        unsafe { *addr }
+        Ok(phys_addr.with_offset(page_offset))
     }
-    fn mutate<T>(&mut self, addr: PhysAddr) -> &mut T {
-        let a = addr.0 as usize;
-        let len = core::mem::size_of::<T>();
 
-        if a + len > self.memory.len() {
-            panic!("memory access out of bounds...");
+    pub fn read_phys(&mut self, addr: PhysAddr) -> u8 {
+        if !self.memory.is_cacheable(addr) {
+            self.log.log(format!("MMIO read at {addr}, bypassing cache"));
+            let mut byte = [0u8];
+            self.memory.read_slice(addr, &mut byte);
+            return byte[0];
         }
 
-        let addr = (&mut self.memory[a]) as *mut u8 as *mut T;
-
-        unsafe { addr.as_mut().unwrap() }
-    }
-}
+        let line_size = self.cache.line_size() as u64;
+        let offset = (addr.bits() % line_size) as usize;
 
-#[derive(Copy, Clone)]
-pub struct TlbEntry {
-    valid: bool,
-    access: u8,
-    tag: u64,
-    addr: PhysAddr,
-}
-impl TlbEntry {
-    pub fn mark_accessed(&mut self) {
-        self.access = self.access.saturating_add(1);
-    }
-    pub const fn invalid() -> Self {
-        Self {
-            valid: false,
-            access: 0,
-            tag: 0,
-            addr: PhysAddr(0),
+        if let Some(line) = self.cache.lookup(addr, &mut self.stats.l1) {
+            self.log.log("Cache Hit");
+            return line.data[offset];
         }
-    }
-}
 
-pub type TlbEntrySet = [TlbEntry; 4];
+        self.log.log("Cache Miss");
 
-pub struct Tlb {
-    sets: [TlbEntrySet; 128],
-}
-impl Index<u64> for Tlb {
-    type Output = TlbEntrySet;
+        let block_addr = PhysAddr::from_bits(addr.bits() - addr.bits() % line_size);
+        let mut data = vec![0u8; line_size as usize];
+        self.memory.read_slice(block_addr, &mut data);
 
-    fn index(&self, index: u64) -> &Self::Output {
-        &self.sets[index as usize]
-    }
-}
-impl IndexMut<u64> for Tlb {
-    fn index_mut(&mut self, index: u64) -> &mut Self::Output {
-        &mut self.sets[index as usize]
-    }
-}
-impl Tlb {
-    pub const fn empty() -> Self {
-        Self {
-            sets: [[TlbEntry::invalid(); 4]; 128],
-        }
-    }
-}
+        self.log.log(format!("Loaded {block_addr} into cache."));
 
-impl Display for Tlb {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut empty = true;
-
-        for (i, set) in self.sets.iter().enumerate() {
-            if set.iter().any(|e| e.valid) {
-                let mut first = true;
-                for entry in set.iter() {
-                    if entry.valid {
-                        empty = false;
-                        if !first {
-                            write!(f, " ")?;
-                        }
-                        first = false;
-
-                        let virt = (entry.tag | i as u64) << 12;
-
-                        write!(
-                            f,
-                            "[{} -> {}, {}]",
-                            VirtAddr(virt),
-                            entry.addr,
-                            entry.access
-                        )?;
-                    }
-                }
-                write!(f, "\n")?;
-            }
-        }
+        let byte = data[offset];
 
-        if empty {
-            write!(f, "(empty)")?;
+        let line = CacheLine { data, dirty: false };
+        if let Some((evicted_line, evicted)) =
+            self.cache.insert(addr, line, self.policy, &mut self.rng)
+        {
+            self.writeback_if_dirty(evicted_line, evicted, line_size);
         }
-        Ok(())
-    }
-}
 
-pub struct CacheStats {
-    hit: usize,
-    miss: usize,
-}
-impl CacheStats {
-    fn new() -> Self {
-        Self { hit: 0, miss: 0 }
+        byte
     }
-    fn hit(&mut self) {
-        self.hit += 1;
-    }
-    fn miss(&mut self) {
-        self.miss += 1;
-    }
-}
 
-pub struct Stats {
-    page_faults: usize,
-    l1: CacheStats,
-    tlb: CacheStats,
-}
-impl Stats {
-    fn new() -> Self {
-        Self {
-            page_faults: 0,
-            l1: CacheStats::new(),
-            tlb: CacheStats::new(),
+    pub fn write_phys(&mut self, addr: PhysAddr, value: u8) {
+        if !self.memory.is_cacheable(addr) {
+            self.log.log(format!("MMIO write at {addr}, bypassing cache"));
+            self.memory.write_slice(addr, &[value]);
+            return;
         }
-    }
-    fn reset(&mut self) {
-        *self = Self::new();
-    }
-}
 
-struct Log {
-    enable: bool,
-    depth: usize,
-}
-impl Log {
-    pub fn new() -> Self {
-        Self {
-            enable: true,
-            depth: 0,
-        }
-    }
-    pub fn log(&self, msg: impl ToString) {
-        if self.enable {
-            println!("{}{}", "  ".repeat(self.depth), msg.to_string());
-        }
-    }
-    pub fn begin_context(&mut self) {
-        self.depth += 1;
-    }
-    pub fn end_context(&mut self) {
-        self.depth -= 1;
-    }
-}
-
-pub struct Machine {
-    cr3: PhysAddr,
-    tlb: Tlb,
-    memory: Memory,
-    cache: L1dCache,
-    stats: Stats,
-    log: Log,
-}
-
-#[derive(Copy, Clone, Debug)]
-pub struct PageFault;
-
-impl Machine {
-    pub fn translate(&mut self, virt_addr: VirtAddr) -> Result<PhysAddr, PageFault> {
-        let tlb_index = virt_addr.virtual_page_number() & 127;
-        let tlb_tag = (virt_addr.virtual_page_number() >> 7) << 7;
-        let tlb_set = &mut self.tlb[tlb_index];
-        let page_offset = virt_addr.page_offset();
+        let line_size = self.cache.line_size() as u64;
 
-        for i in 0..4 {
-            if tlb_set[i].tag == tlb_tag && tlb_set[i].valid {
-                tlb_set[i].mark_accessed();
-                self.stats.tlb.hit();
-                self.log.log("TLB Hit");
-                return Ok(tlb_set[i].addr.with_offset(page_offset));
-            }
+        if self.cache.write(addr, value, &mut self.stats.l1) {
+            self.log.log("Cache Write Hit");
+            return;
         }
 
-        self.stats.tlb.miss();
-        self.log.log("TLB Miss");
+        self.log.log("Cache Write Miss");
 
-        self.stats.page_faults += 1;
-
-        let addr1 = self.cr3;
-        let page_table_1 = self.memory.read::<PageTable>(addr1);
-        let pte1 = page_table_1[virt_addr.vpn1()];
-        if !pte1.is_present() {
-            return Err(PageFault);
-        }
-
-        let addr2 = pte1.phys_addr();
-        let page_table_2 = self.memory.read::<PageTable>(addr2);
-        let pte2 = page_table_2[virt_addr.vpn2()];
-        if !pte2.is_present() {
-            return Err(PageFault);
-        }
+        let offset = (addr.bits() % line_size) as usize;
+        let block_addr = PhysAddr::from_bits(addr.bits() - addr.bits() % line_size);
+        let mut data = vec![0u8; line_size as usize];
+        self.memory.read_slice(block_addr, &mut data);
+        data[offset] = value;
 
-        let addr3 = pte2.phys_addr();
-        let page_table_3 = self.memory.read::<PageTable>(addr3);
-        let pte3 = page_table_3[virt_addr.vpn3()];
-        if !pte3.is_present() {
-            return Err(PageFault);
-        }
+        self.log
+            .log(format!("Loaded {block_addr} into cache for write-allocate."));
 
-        let addr4 = pte3.phys_addr();
-        let page_table_4 = self.memory.read::<PageTable>(addr4);
-        let pte4 = page_table_4[virt_addr.vpn4()];
-        if !pte4.is_present() {
-            return Err(PageFault);
+        if let Some((evicted_line, evicted)) =
+            self.cache
+                .insert(addr, CacheLine { data, dirty: true }, self.policy, &mut self.rng)
+        {
+            self.writeback_if_dirty(evicted_line, evicted, line_size);
         }
-
-        self.stats.page_faults -= 1;
-
-        let phys_addr = pte4.phys_addr();
-
-        let mut k = 0;
-        for i in 0..4 {
-            if !tlb_set[i].valid {
-                // if not valid, chose this entry
-                k = i;
-                break;
-            }
-
-            // choose entry with least accesses
-            if tlb_set[i].access < tlb_set[k].access {
-                k = i;
-            }
-        }
-
-        for i in 0..4 {
-            // reset access counter
-            tlb_set[i].access = 0;
-        }
-
-        if tlb_set[k].valid {
-            let evicted = VirtAddr((tlb_set[k].tag | tlb_index) << 12);
-            self.log.log(format!("Evicting TLB Entry {evicted}"));
-        }
-
-        // replace old entry
-        tlb_set[k].valid = true;
-        tlb_set[k].addr = phys_addr;
-        tlb_set[k].tag = tlb_tag;
-        tlb_set[k].mark_accessed();
-
-        self.log.log(format!("New TLB Entry: {virt_addr}"));
-
-        Ok(phys_addr.with_offset(page_offset))
     }
 
-    pub fn read_phys(&mut self, addr: PhysAddr) -> u8 {
-        let offset = addr.frame_offset() & 63;
-        let index = (addr.frame_offset() >> 6) & 63;
-        let tag = addr.frame_number();
-
-        let cache_set = &mut self.cache.entries[index];
-
-        // cache associativity (imagine this happens in parallel)
-        for i in 0..8 {
-            if cache_set[i].valid && cache_set[i].tag == tag {
-                self.stats.l1.hit();
-                self.log.log("Cache Hit");
-                return cache_set[i].line[offset];
-            }
-        }
-
-        self.stats.l1.miss();
-        self.log.log("Cache Miss");
-
-        // none is the value we need
-        // --> evict some entry
-        // TODO: better eviction strategy
-
-        let mut k = 0;
-        for i in 0..8 {
-            if !cache_set[i].valid {
-                k = i;
-            }
-        }
-
-        if cache_set[k].valid {
-            let evicted = PhysAddr(cache_set[k].tag << 12 | (index as u64) << 6);
-            self.log.log(format!("Evicting L1 Entry: {evicted}"));
+    /// Writes an evicted L1 line back to memory if it's dirty, otherwise
+    /// drops it silently; either way logs at the level appropriate for it.
+    fn writeback_if_dirty(&mut self, evicted_line: u64, evicted: CacheLine, line_size: u64) {
+        let evicted_addr = PhysAddr::from_bits(evicted_line * line_size);
+        self.stats.l1.evict();
+        if evicted.dirty {
+            self.memory.write_slice(evicted_addr, &evicted.data);
+            self.stats.l1.write_back();
+            self.log
+                .log(format!("Writing back dirty L1 Entry: {evicted_addr}"));
+        } else {
+            self.log.log(format!("Evicting L1 Entry: {evicted_addr}"));
         }
+    }
 
-        let block_addr = PhysAddr(addr.0 & !63);
+    pub fn read(&mut self, addr: VirtAddr) -> Result<u8, Fault> {
+        self.log.log(format!("Memory Access at {addr}"));
+        self.log.begin_context();
 
-        cache_set[k].valid = true;
-        cache_set[k].tag = tag;
-        cache_set[k].line = self.memory.read(block_addr);
+        let phys_addr = self.translate(addr, AccessKind::Load, self.privilege)?;
+        self.log.log(format!("Found physical address {phys_addr}"));
 
-        self.log.log(format!("Loaded {block_addr} into cache."));
+        let byte = self.read_phys(phys_addr);
 
-        cache_set[k].line[offset]
+        self.log.end_context();
+        Ok(byte)
     }
 
-    pub fn read(&mut self, addr: VirtAddr) -> Result<u8, PageFault> {
-        self.log.log(format!("Memory Access at {addr}"));
+    pub fn write(&mut self, addr: VirtAddr, value: u8) -> Result<(), Fault> {
+        self.log.log(format!("Memory Write at {addr} = {value:#04x}"));
         self.log.begin_context();
 
-        let phys_addr = self.translate(addr)?;
+        let phys_addr = self.translate(addr, AccessKind::Store, self.privilege)?;
         self.log.log(format!("Found physical address {phys_addr}"));
 
-        let byte = self.read_phys(phys_addr);
+        self.write_phys(phys_addr, value);
 
         self.log.end_context();
-        Ok(byte)
+        Ok(())
     }
 
     pub fn invalidate_tlb(&mut self) {
         self.log.log("Invalidate TLB");
-        self.tlb = Tlb::empty();
+        self.tlb = Tlb::new(128, 4);
     }
 
     pub fn map_page(
@@ -527,125 +269,116 @@ impl Machine {
         table_location: PhysAddr,
         table_entry: usize,
         target_frame: PhysAddr,
+        flags: PteFlags,
     ) {
         self.log.log(format!("Page-Table Edit at address {table_location}: Mapping entry {table_entry:03} to {target_frame}"));
-        let table = self.memory.mutate::<PageTable>(table_location);
-        table[table_entry] = PageTableEntry::new_present(target_frame);
+        self.write_pte(table_location, table_entry, target_frame, flags);
     }
 
-    pub fn unmap_page(&mut self, table_location: PhysAddr, table_entry: usize) {
+    /// Maps `table_entry` as a huge-page leaf found at `level` (1 = root)
+    /// rather than at the deepest level, covering the whole region a
+    /// megapage/gigapage/terapage at that level spans.
+    pub fn map_huge_page(
+        &mut self,
+        table_location: PhysAddr,
+        table_entry: usize,
+        target_frame: PhysAddr,
+        level: usize,
+        flags: PteFlags,
+    ) {
+        let region_size = 1u64 << self.mode.region_width(level);
         self.log.log(format!(
-            "Page-Table Edit at address {table_location}: Unmapping entry {table_entry:03}"
+            "Page-Table Edit at address {table_location}: Mapping entry {table_entry:03} to {target_frame} ({region_size}B huge page)"
         ));
-        let table = self.memory.mutate::<PageTable>(table_location);
-        table[table_entry] = PageTableEntry::new_unmapped();
+        self.write_pte(table_location, table_entry, target_frame, flags);
     }
-}
 
-// pretty printing
-impl Machine {
-    pub fn page_map(&self) -> String {
-        let mut buf = String::new();
-        self.page_map_rec(&mut buf, 1, self.cr3, VirtAddr(0));
-        buf
-    }
-    fn num_mapped_entries(&self, table_location: PhysAddr) -> usize {
-        let table = self.memory.read::<PageTable>(table_location);
-        table.iter().filter(|e| e.is_present()).count()
+    /// Shared page-table write backing both `map_page` and `map_huge_page`,
+    /// which differ only in the log message they emit beforehand.
+    fn write_pte(
+        &mut self,
+        table_location: PhysAddr,
+        table_entry: usize,
+        target_frame: PhysAddr,
+        flags: PteFlags,
+    ) {
+        let entry_addr = PageTableEntry::addr_in_table(table_location, table_entry);
+        *self.memory.edit::<PageTableEntry>(entry_addr) =
+            PageTableEntry::new_present_with_flags(target_frame, flags);
     }
-    fn page_map_rec(&self, buf: &mut String, depth: i32, phys_base: PhysAddr, virt_base: VirtAddr) {
-        let table = self.memory.read::<PageTable>(phys_base);
 
-        let indent = " | ".repeat(depth as usize - 1);
-        let stride = 4096 << (9 * (4 - depth));
-
-        for (i, entry) in table.iter().enumerate() {
-            if !entry.is_present() {
-                continue;
-            }
-
-            let virt = virt_base + stride * i as u64;
-            let phys = entry.phys_addr();
-            if depth == 4 {
-                *buf += &(format!("{indent}{i:03}: {virt} -> {phys}\n"));
-            } else {
-                let x = self.num_mapped_entries(phys);
-                *buf += &format!("{indent}{i:03}: [{x} mapped entries]\n");
-                self.page_map_rec(buf, depth + 1, phys, virt);
-            }
-        }
+    pub fn unmap_page(&mut self, table_location: PhysAddr, table_entry: usize) {
+        self.log.log(format!(
+            "Page-Table Edit at address {table_location}: Unmapping entry {table_entry:03}"
+        ));
+        let entry_addr = PageTableEntry::addr_in_table(table_location, table_entry);
+        *self.memory.edit::<PageTableEntry>(entry_addr) = PageTableEntry::new_unmapped();
     }
 }
 
 impl Machine {
     fn stats(&self) -> String {
         format!(
-            "TLB hits:    {}\nTLB misses:  {}\nL1 hits:     {}\nL1 misses:   {}\nPage Faults: {}",
+            "Replacement policy: {:?}\nTLB hits:         {}\nTLB misses:       {} (cold: {}, asid conflict: {})\nTLB evictions:    {}\nL1 hits:          {}\nL1 misses:        {}\nL1 write hits:    {}\nL1 write misses:  {}\nL1 write-backs:   {}\nL1 evictions:     {}\nPage Faults:      {}",
+            self.policy,
             self.stats.tlb.hit,
             self.stats.tlb.miss,
+            self.stats.tlb.miss_cold,
+            self.stats.tlb.miss_asid_conflict,
+            self.stats.tlb.evictions,
             self.stats.l1.hit,
             self.stats.l1.miss,
+            self.stats.l1.write_hit,
+            self.stats.l1.write_miss,
+            self.stats.l1.write_back,
+            self.stats.l1.evictions,
             self.stats.page_faults
         )
     }
     fn dump_stats(&mut self) {
-        println!("{}", boxed("Pages", &self.page_map()));
-        println!("{}", boxed("TLB", &format!("{}", self.tlb)));
-        println!("{}", boxed("L1-Cache", &self.cache.display()));
-        println!("{}", boxed("Stats", &self.stats()));
+        print_box("Pages", self.draw_page_map());
+        print_box("TLB", self.tlb.draw());
+        print_box("L1-Cache", self.cache.draw());
+        print_box("Stats", self.stats());
         self.stats.reset();
     }
 }
 
-fn boxed(title: &str, content: &str) -> String {
-    let lines: Vec<_> = content.lines().collect();
-    let width = lines
-        .iter()
-        .map(|l| l.len())
-        .max()
-        .unwrap_or(0)
-        .max(4 + title.len());
-    let mut buf = String::new();
-
-    let width = width + 1;
-
-    buf += "╭─";
-    buf += title;
-    for _ in 0..(width - title.len()) {
-        buf += "─";
-    }
-    buf += "╮\n";
-
-    for line in lines {
-        buf += "│ ";
-        buf += line;
-        for _ in 0..(width - line.len()) {
-            buf += " ";
-        }
-        buf += "│\n";
-    }
-    buf += "╰";
-    for _ in 0..=width {
-        buf += "─";
-    }
-    buf += "╯";
-
-    buf
-}
-
 #[derive(Copy, Clone)]
 pub enum Action {
     Map {
         table: PhysAddr,
         index: usize,
         target: PhysAddr,
+        flags: PteFlags,
+    },
+    /// Like `Map`, but the entry is a huge-page leaf at `level` (1 = root)
+    /// instead of an ordinary 4 KiB page at the deepest level.
+    MapHuge {
+        table: PhysAddr,
+        index: usize,
+        target: PhysAddr,
+        level: usize,
+        flags: PteFlags,
     },
     UnMap {
         table: PhysAddr,
         index: usize,
     },
     InvalidateTlb,
+    /// Switches the privilege level subsequent `Read`s and `Write`s are
+    /// performed at.
+    SetPrivilege(PrivilegeLevel),
+    /// Switches the current ASID, as if writing `satp`.
+    SwitchAsid(u16),
+    /// Switches the eviction policy used by both the TLB and the L1 cache
+    /// from this point on.
+    SetReplacementPolicy(ReplacementPolicy),
+    /// `SFENCE.VMA`-style targeted flush: invalidates non-global TLB entries
+    /// for `asid`, optionally restricted to a single page.
+    FlushTlb { asid: u16, addr: Option<VirtAddr> },
     Read(VirtAddr),
+    Write { addr: VirtAddr, value: u8 },
     DumpStats,
 }
 impl Machine {
@@ -655,17 +388,49 @@ impl Machine {
                 table,
                 index,
                 target,
+                flags,
             } => {
-                self.map_page(table, index, target);
+                self.map_page(table, index, target, flags);
+            }
+            Action::MapHuge {
+                table,
+                index,
+                target,
+                level,
+                flags,
+            } => {
+                self.map_huge_page(table, index, target, level, flags);
             }
             Action::UnMap { table, index } => self.unmap_page(table, index),
             Action::Read(addr) => {
                 self.read(addr);
             }
+            Action::Write { addr, value } => {
+                self.write(addr, value);
+            }
             Action::DumpStats => {
                 self.dump_stats();
             }
             Action::InvalidateTlb => self.invalidate_tlb(),
+            Action::SetPrivilege(privilege) => {
+                self.log.log(format!("Switching to {privilege:?} mode"));
+                self.privilege = privilege;
+            }
+            Action::SwitchAsid(asid) => {
+                self.log.log(format!("Switching to ASID {asid}"));
+                self.tlb.switch_asid(asid);
+            }
+            Action::SetReplacementPolicy(policy) => {
+                self.log
+                    .log(format!("Switching to {policy:?} replacement policy"));
+                self.policy = policy;
+            }
+            Action::FlushTlb { asid, addr } => {
+                let target = addr.map_or("all pages".to_string(), |a| a.to_string());
+                self.log
+                    .log(format!("SFENCE.VMA asid={asid} addr={target}"));
+                self.tlb.flush(asid, addr);
+            }
         }
     }
     pub fn run_many(&mut self, actions: &[Action]) {
@@ -675,21 +440,36 @@ impl Machine {
     }
 }
 
+/// Demo page-fault handler: behaves like the default (allocate a zeroed
+/// page), but logs which page it was asked about.
+struct LogOnFault;
+impl HandlePageFault for LogOnFault {
+    fn handle_page_fault(&mut self, page: u64) -> PageFaultResolution {
+        println!("page fault handler: allocating page {page}");
+        PageFaultResolution::Allocate
+    }
+}
+
 fn main() {
     let mut allocator = 99;
     let mut next_page = || {
         allocator += 1;
-        PhysAddr(4096 * allocator)
+        PhysAddr::from_bits(4096 * allocator)
     };
 
     let mut mmu = Machine {
         cr3: next_page(),
-        tlb: Tlb::empty(),
+        mode: PagingMode::Sv48,
+        privilege: PrivilegeLevel::Supervisor,
+        tlb: Tlb::new(128, 4),
         memory: Memory::megabytes(200),
-        cache: L1dCache::empty(),
+        cache: L1Cache::new(64, 8, 64),
+        policy: ReplacementPolicy::Lru,
+        rng: Rng::new(0x5eed_1234_cafe_f00d),
         stats: Stats::new(),
-        log: Log::new(),
+        log: Log::new(StdoutTrace),
     };
+    mmu.memory.set_page_fault_handler(LogOnFault);
 
     let p1 = next_page();
     let p2 = next_page();
@@ -701,121 +481,211 @@ fn main() {
     let p8 = next_page();
     let p9 = next_page();
 
+    // A one-register device: reads always return 0xAB, writes are dropped.
+    let mmio_frame = next_page();
+    mmu.memory.register_mmio(MmioRegion::new(
+        mmio_frame,
+        4096,
+        |_offset| 0xAB,
+        |_offset, _value| {},
+    ));
+
+    // Non-leaf entries only point at the next-level table: no R/W/X.
+    let table_flags = PteFlags::empty();
+    // Leaf entries are the actual data pages the demo reads through.
+    let leaf_flags = PteFlags::READ | PteFlags::WRITE | PteFlags::EXEC | PteFlags::USER;
+
     use Action::*;
     let actions = [
         Map {
             table: mmu.cr3,
             index: 10,
             target: p1,
+            flags: table_flags,
         },
         Map {
             table: p1,
             index: 0,
             target: p2,
+            flags: table_flags,
         },
         // we map p2[0] and p2[1] to p3 to simulate homonyms
         Map {
             table: p2,
             index: 0,
             target: p3,
+            flags: table_flags,
         },
         Map {
             table: p2,
             index: 1,
             target: p3,
+            flags: table_flags,
         },
         Map {
             table: p3,
             index: 0,
             target: p4,
+            flags: leaf_flags,
         },
         Map {
             table: p3,
             index: 1,
             target: p5,
+            flags: leaf_flags,
         },
         Map {
             table: p3,
             index: 2,
             target: p6,
+            flags: leaf_flags,
         },
         Map {
             table: mmu.cr3,
             index: 32,
             target: p7,
+            flags: table_flags,
         },
         Map {
             table: p7,
             index: 0,
             target: p8,
+            flags: table_flags,
         },
         Map {
             table: p8,
             index: 0,
             target: p9,
+            flags: table_flags,
         },
         Map {
             table: p9,
             index: 200,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 201,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 202,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 203,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 204,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 205,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 206,
             target: next_page(),
+            flags: leaf_flags,
         },
         Map {
             table: p9,
             index: 207,
             target: next_page(),
+            flags: leaf_flags,
+        },
+        Map {
+            table: p9,
+            index: 208,
+            target: mmio_frame,
+            flags: leaf_flags,
         },
         InvalidateTlb,
-        Read(VirtAddr(0x50000000000)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x50000202200)),
-        Read(VirtAddr(0x1000000c8000)),
-        Read(VirtAddr(0x1000000c8000 + 64)),
-        Read(VirtAddr(0x1000000c8000 + 2 * 64)),
-        Read(VirtAddr(0x1000000c8100)),
-        Read(VirtAddr(0x1000000c8200)),
-        Read(VirtAddr(0x1000000c9000)),
-        Read(VirtAddr(0x1000000ca000)),
-        Read(VirtAddr(0x1000000cb000)),
-        Read(VirtAddr(0x1000000cc000)),
-        Read(VirtAddr(0x1000000cd000)),
-        Read(VirtAddr(0x1000000ce000)),
-        Read(VirtAddr(0x1000000cf000)),
+        Read(VirtAddr::from_bits(0x50000000000)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x50000202200)),
+        Read(VirtAddr::from_bits(0x1000000c8000)),
+        Read(VirtAddr::from_bits(0x1000000c8000 + 64)),
+        Read(VirtAddr::from_bits(0x1000000c8000 + 2 * 64)),
+        Read(VirtAddr::from_bits(0x1000000c8100)),
+        Read(VirtAddr::from_bits(0x1000000c8200)),
+        Read(VirtAddr::from_bits(0x1000000c9000)),
+        Read(VirtAddr::from_bits(0x1000000ca000)),
+        Read(VirtAddr::from_bits(0x1000000cb000)),
+        Read(VirtAddr::from_bits(0x1000000cc000)),
+        Read(VirtAddr::from_bits(0x1000000cd000)),
+        Read(VirtAddr::from_bits(0x1000000ce000)),
+        Read(VirtAddr::from_bits(0x1000000cf000)),
+        Write {
+            addr: VirtAddr::from_bits(0x1000000c8000),
+            value: 0x42,
+        },
+        Write {
+            addr: VirtAddr::from_bits(0x1000000c8000),
+            value: 0x43,
+        },
+        Write {
+            addr: VirtAddr::from_bits(0x50000202200),
+            value: 0x44,
+        },
+        Read(VirtAddr::from_bits(0x1000000d0000)),
+        Write {
+            addr: VirtAddr::from_bits(0x1000000d0000),
+            value: 0x07,
+        },
+        SetReplacementPolicy(ReplacementPolicy::Fifo),
+        Read(VirtAddr::from_bits(0x1000000cc000)),
+        SetReplacementPolicy(ReplacementPolicy::Random),
+        Read(VirtAddr::from_bits(0x1000000cd000)),
         DumpStats,
     ];
 
     mmu.run_many(&actions);
+
+    // Demonstrate the region-permission, cursor, and bulk byte-transfer APIs
+    // directly against physical memory (they don't go through an `Action`).
+    let ro_page = next_page();
+    mmu.memory
+        .set_permissions(ro_page..PhysAddr::from_bits(ro_page.bits() + 4096), Perm::READ);
+    mmu.log.log(format!("Restricted {ro_page} to read-only"));
+    match mmu.memory.try_write(ro_page, 0u8) {
+        Ok(()) => mmu.log.log("Unexpectedly allowed a write to a read-only page"),
+        Err(err) => mmu.log.log(format!("Write to read-only page denied: {err:?}")),
+    }
+
+    let cursor_page = next_page();
+    {
+        let mut writer = mmu.memory.writer(cursor_page);
+        writer.write::<u32>(0xDEAD_BEEF).unwrap();
+        writer.write::<u16>(0xBEEF).unwrap();
+    }
+    {
+        let mut reader = mmu.memory.reader(cursor_page);
+        let a: u32 = reader.read().unwrap();
+        let b: u16 = reader.read().unwrap();
+        mmu.log.log(format!("Cursor read back {a:#x}, {b:#x}"));
+    }
+
+    let bytes_page = next_page();
+    mmu.memory.write_bytes(bytes_page, b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    mmu.memory.read_bytes(bytes_page, &mut buf).unwrap();
+    mmu.log
+        .log(format!("Bulk round-trip: {:?}", core::str::from_utf8(&buf)));
 }
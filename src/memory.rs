@@ -1,37 +1,434 @@
+use core::ops::{BitOr, Range};
+use std::collections::HashMap;
 use crate::addr::PhysAddr;
 
+const PAGE_SIZE: u64 = 4096;
+
+/// Why a typed `Memory` access failed.
+#[derive(Copy, Clone, Debug)]
+pub enum MemoryError {
+    /// The access would read or write past the addressable size.
+    OutOfBounds { addr: PhysAddr, len: usize },
+    /// `addr` isn't aligned to the accessed type's alignment.
+    Misaligned,
+    /// The access would straddle two 4 KiB pages; the sparse per-page
+    /// backing store can't satisfy it.
+    CrossesPageBoundary { addr: PhysAddr, len: usize },
+    /// `addr` falls in a region whose permissions lack `READ`.
+    AddressNotReadable(PhysAddr),
+    /// `addr` falls in a region whose permissions lack `WRITE`.
+    AddressNotWritable(PhysAddr),
+    /// `addr` falls in a region whose permissions lack `EXECUTE`.
+    AddressNotExecutable(PhysAddr),
+}
+
+/// What to do about an access that hit a page with no backing storage yet.
+pub enum PageFaultResolution {
+    /// Allocate a zeroed page and retry the access against it.
+    Allocate,
+    /// Allocate a page pre-filled with `data` (e.g. read in from a
+    /// memory-mapped file) and retry the access against it.
+    AllocateWith(Box<[u8; PAGE_SIZE as usize]>),
+    /// Fail the access with this error instead of allocating anything.
+    Deny(MemoryError),
+}
+
+/// Invoked the first time a write touches a page with no backing storage
+/// yet. Reads of never-touched pages don't consult this — they read as zero
+/// without allocating (see `try_read`).
+pub trait HandlePageFault {
+    fn handle_page_fault(&mut self, page: u64) -> PageFaultResolution;
+}
+
+/// RWX access permission bits for a region of physical memory, analogous to
+/// `PteFlags` but for `Memory`'s own protection layer rather than a PTE.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Perm(u8);
+
+impl Perm {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXECUTE: Self = Self(1 << 2);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn all() -> Self {
+        Self(Self::READ.0 | Self::WRITE.0 | Self::EXECUTE.0)
+    }
+    pub const fn contains(self, flag: Self) -> bool {
+        self.0 & flag.0 == flag.0
+    }
+}
+
+impl BitOr for Perm {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A region of physical address space backed by device callbacks instead of
+/// plain RAM. Registered via `register_mmio`; `read_slice`/`write_slice`
+/// dispatch to it, and it's never cached (see `is_cacheable`).
+pub struct MmioRegion {
+    base: PhysAddr,
+    size: u64,
+    read: Box<dyn FnMut(u64) -> u8>,
+    write: Box<dyn FnMut(u64, u8)>,
+}
+
+impl MmioRegion {
+    pub fn new(
+        base: PhysAddr,
+        size: u64,
+        read: impl FnMut(u64) -> u8 + 'static,
+        write: impl FnMut(u64, u8) + 'static,
+    ) -> Self {
+        Self {
+            base,
+            size,
+            read: Box::new(read),
+            write: Box::new(write),
+        }
+    }
+
+    fn contains(&self, addr: PhysAddr) -> bool {
+        addr.bits().wrapping_sub(self.base.bits()) < self.size
+    }
+}
+
 pub struct Memory {
-    memory: Vec<u8>,
+    /// Total addressable size in bytes, for bounds checks. Pages within it
+    /// are backed lazily; most of this range has no entry in `pages`.
+    size: u64,
+    /// Sparse backing store, keyed by page number (`addr / PAGE_SIZE`). A
+    /// missing entry reads as a page of zeroes; see `try_read`/`ensure_page`.
+    pages: HashMap<u64, Box<[u8; PAGE_SIZE as usize]>>,
+    /// Consulted the first time a write touches a page missing from `pages`.
+    page_fault_handler: Option<Box<dyn HandlePageFault>>,
+    /// Device-backed regions, checked before falling back to `pages`.
+    mmio: Vec<MmioRegion>,
+    /// Per-region RWX permissions, sorted by `start` for `permissions_at`'s
+    /// `partition_point` lookup. Addresses outside every configured region
+    /// default to `Perm::all()`.
+    permissions: Vec<(Range<PhysAddr>, Perm)>,
 }
 
 impl Memory {
     pub fn megabytes(mb: usize) -> Self {
         Self {
-            memory: vec![0; mb << 20],
+            size: (mb as u64) << 20,
+            pages: HashMap::new(),
+            page_fault_handler: None,
+            mmio: Vec::new(),
+            permissions: Vec::new(),
         }
     }
-    pub fn read<T: Copy>(&self, addr: PhysAddr) -> T {
-        let a = addr.bits() as usize;
+
+    /// Registers the callback consulted the first time a write touches a
+    /// page with no backing storage yet.
+    pub fn set_page_fault_handler(&mut self, handler: impl HandlePageFault + 'static) {
+        self.page_fault_handler = Some(Box::new(handler));
+    }
+
+    fn page_number(addr: u64) -> u64 {
+        addr / PAGE_SIZE
+    }
+    fn page_offset(addr: u64) -> usize {
+        (addr % PAGE_SIZE) as usize
+    }
+
+    /// Ensures `page` has backing storage, consulting `page_fault_handler`
+    /// (or just allocating a zeroed page) if it doesn't yet.
+    fn ensure_page(&mut self, page: u64) -> Result<(), MemoryError> {
+        if self.pages.contains_key(&page) {
+            return Ok(());
+        }
+
+        let resolution = match &mut self.page_fault_handler {
+            Some(handler) => handler.handle_page_fault(page),
+            None => PageFaultResolution::Allocate,
+        };
+
+        match resolution {
+            PageFaultResolution::Allocate => {
+                self.pages.insert(page, Box::new([0u8; PAGE_SIZE as usize]));
+            }
+            PageFaultResolution::AllocateWith(data) => {
+                self.pages.insert(page, data);
+            }
+            PageFaultResolution::Deny(err) => return Err(err),
+        }
+
+        Ok(())
+    }
+
+    /// Restricts `range` to `perms` going forward. Any existing region
+    /// overlapping `range` is dropped rather than split.
+    pub fn set_permissions(&mut self, range: Range<PhysAddr>, perms: Perm) {
+        self.permissions
+            .retain(|(r, _)| r.end <= range.start || r.start >= range.end);
+        self.permissions.push((range, perms));
+        self.permissions.sort_by_key(|(r, _)| r.start);
+    }
+
+    /// The permission bits in effect at `addr`: `Perm::all()` unless `addr`
+    /// falls inside a region configured via `set_permissions`.
+    fn permissions_at(&self, addr: PhysAddr) -> Perm {
+        let i = self
+            .permissions
+            .partition_point(|(r, _)| r.start <= addr);
+        match i.checked_sub(1).map(|i| &self.permissions[i]) {
+            Some((r, perm)) if addr < r.end => *perm,
+            _ => Perm::all(),
+        }
+    }
+
+    /// Registers an MMIO region; addresses inside it are dispatched to its
+    /// callbacks rather than the backing pages.
+    pub fn register_mmio(&mut self, region: MmioRegion) {
+        self.mmio.push(region);
+    }
+
+    fn mmio_at(&mut self, addr: PhysAddr) -> Option<&mut MmioRegion> {
+        self.mmio.iter_mut().find(|r| r.contains(addr))
+    }
+
+    /// Whether `addr` is backed by ordinary RAM rather than a device
+    /// register. MMIO must never be cached: reads can have side effects and
+    /// writes must reach the device immediately.
+    pub fn is_cacheable(&self, addr: PhysAddr) -> bool {
+        !self.mmio.iter().any(|r| r.contains(addr))
+    }
+
+    /// Checks that a `T`-sized access at `addr` is in bounds, aligned, and
+    /// permitted to perform `required`. Returns `addr` as a plain `u64`.
+    fn bounds_check<T>(&self, addr: PhysAddr, required: Perm) -> Result<u64, MemoryError> {
+        let a = addr.bits();
         let len = core::mem::size_of::<T>();
 
-        if a + len > self.memory.len() {
-            panic!("memory access out of bounds...");
+        if a + len as u64 > self.size {
+            return Err(MemoryError::OutOfBounds { addr, len });
+        }
+        if len > 0 && Self::page_number(a) != Self::page_number(a + len as u64 - 1) {
+            return Err(MemoryError::CrossesPageBoundary { addr, len });
+        }
+        if a % core::mem::align_of::<T>() as u64 != 0 {
+            return Err(MemoryError::Misaligned);
+        }
+
+        let perm = self.permissions_at(addr);
+        if required.contains(Perm::READ) && !perm.contains(Perm::READ) {
+            return Err(MemoryError::AddressNotReadable(addr));
+        }
+        if required.contains(Perm::WRITE) && !perm.contains(Perm::WRITE) {
+            return Err(MemoryError::AddressNotWritable(addr));
+        }
+        if required.contains(Perm::EXECUTE) && !perm.contains(Perm::EXECUTE) {
+            return Err(MemoryError::AddressNotExecutable(addr));
+        }
+
+        Ok(a)
+    }
+
+    /// Reads a `T` out of the page containing `a`, treating a missing page
+    /// as all zeroes (never allocates; see `ensure_page` for the write side).
+    fn read_unchecked<T: Copy>(&self, a: u64) -> T {
+        let page = Self::page_number(a);
+        let offset = Self::page_offset(a);
+        match self.pages.get(&page) {
+            Some(data) => unsafe { (data[offset..].as_ptr() as *const T).read_unaligned() },
+            None => unsafe { core::mem::zeroed() },
+        }
+    }
+
+    /// Fallible counterpart to `read`, for an out-of-bounds, misaligned, or
+    /// unreadable access instead of panicking.
+    pub fn try_read<T: Copy>(&self, addr: PhysAddr) -> Result<T, MemoryError> {
+        let a = self.bounds_check::<T>(addr, Perm::READ)?;
+        Ok(self.read_unchecked(a))
+    }
+
+    /// Like `try_read`, but checks `EXECUTE` instead of `READ`, for the
+    /// instruction-fetch path of an emulator built on top of this crate.
+    pub fn try_fetch<T: Copy>(&self, addr: PhysAddr) -> Result<T, MemoryError> {
+        let a = self.bounds_check::<T>(addr, Perm::EXECUTE)?;
+        Ok(self.read_unchecked(a))
+    }
+
+    /// Fallible counterpart to `edit`, for an out-of-bounds, misaligned, or
+    /// unwritable access instead of panicking. Lazily allocates the backing
+    /// page on first write (see `ensure_page`).
+    pub fn try_edit<T>(&mut self, addr: PhysAddr) -> Result<&mut T, MemoryError> {
+        let a = self.bounds_check::<T>(addr, Perm::WRITE)?;
+        let page = Self::page_number(a);
+        let offset = Self::page_offset(a);
+        self.ensure_page(page)?;
+        let data = self.pages.get_mut(&page).unwrap();
+        let ptr = (&mut data[offset..]).as_mut_ptr() as *mut T;
+        Ok(unsafe { ptr.as_mut().unwrap() })
+    }
+
+    /// Fallible whole-value write: `try_edit` plus assignment in one step.
+    pub fn try_write<T>(&mut self, addr: PhysAddr, val: T) -> Result<(), MemoryError> {
+        *self.try_edit(addr)? = val;
+        Ok(())
+    }
+
+    pub fn read<T: Copy>(&self, addr: PhysAddr) -> T {
+        self.try_read(addr).unwrap()
+    }
+
+    /// Byte-level counterpart to `bounds_check`, used by `read_slice`'s/
+    /// `write_slice`'s non-MMIO path so a `set_permissions` region also
+    /// governs the raw byte access `Machine::read_phys`/`write_phys` perform.
+    fn read_byte(&self, addr: u64) -> u8 {
+        let a = match self.bounds_check::<u8>(PhysAddr::from_bits(addr), Perm::READ) {
+            Ok(a) => a,
+            Err(err) => panic!("memory access denied at {addr:#x}: {err:?}"),
+        };
+        match self.pages.get(&Self::page_number(a)) {
+            Some(data) => data[Self::page_offset(a)],
+            None => 0,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u64) -> &mut u8 {
+        let a = match self.bounds_check::<u8>(PhysAddr::from_bits(addr), Perm::WRITE) {
+            Ok(a) => a,
+            Err(err) => panic!("memory access denied at {addr:#x}: {err:?}"),
+        };
+        let page = Self::page_number(a);
+        if let Err(err) = self.ensure_page(page) {
+            panic!("page fault handler denied write at {addr:#x}: {err:?}");
+        }
+        &mut self.pages.get_mut(&page).unwrap()[Self::page_offset(a)]
+    }
+
+    /// Copies `buf.len()` bytes starting at `addr` into `buf`, dispatching to
+    /// an `MmioRegion` if `addr` falls inside one.
+    pub fn read_slice(&mut self, addr: PhysAddr, buf: &mut [u8]) {
+        if let Some(region) = self.mmio_at(addr) {
+            let base = region.base.bits();
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = (region.read)(addr.bits() + i as u64 - base);
+            }
+            return;
         }
 
-        let addr = (&self.memory[a]) as *const u8 as *const T;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read_byte(addr.bits() + i as u64);
+        }
+    }
+    /// Copies `buf` into memory starting at `addr`, dispatching to an
+    /// `MmioRegion` if `addr` falls inside one.
+    pub fn write_slice(&mut self, addr: PhysAddr, buf: &[u8]) {
+        if let Some(region) = self.mmio_at(addr) {
+            let base = region.base.bits();
+            for (i, &byte) in buf.iter().enumerate() {
+                (region.write)(addr.bits() + i as u64 - base, byte);
+            }
+            return;
+        }
 
-        unsafe { *addr }
+        for (i, &byte) in buf.iter().enumerate() {
+            *self.write_byte(addr.bits() + i as u64) = byte;
+        }
     }
     pub fn edit<T>(&mut self, addr: PhysAddr) -> &mut T {
-        let a = addr.bits() as usize;
-        let len = core::mem::size_of::<T>();
+        self.try_edit(addr).unwrap()
+    }
+
+    /// Copies up to `buf.len()` bytes starting at `addr` into `buf`, stopping
+    /// at the first out-of-bounds or unreadable byte instead of failing the
+    /// whole transfer. Returns how many bytes were copied; only `Err` if the
+    /// very first byte couldn't be read.
+    pub fn read_bytes(&self, addr: PhysAddr, buf: &mut [u8]) -> Result<usize, MemoryError> {
+        let mut n = 0;
+        for byte in buf.iter_mut() {
+            let byte_addr = PhysAddr::from_bits(addr.bits() + n as u64);
+            match self.try_read::<u8>(byte_addr) {
+                Ok(val) => *byte = val,
+                Err(err) if n == 0 => return Err(err),
+                Err(_) => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
 
-        if a + len > self.memory.len() {
-            panic!("memory access out of bounds...");
+    /// Write-side counterpart to `read_bytes`; see its docs. Pages lazily
+    /// allocate on write exactly as with `edit`.
+    pub fn write_bytes(&mut self, addr: PhysAddr, data: &[u8]) -> Result<usize, MemoryError> {
+        let mut n = 0;
+        for &byte in data.iter() {
+            let byte_addr = PhysAddr::from_bits(addr.bits() + n as u64);
+            match self.try_write(byte_addr, byte) {
+                Ok(()) => n += 1,
+                Err(err) if n == 0 => return Err(err),
+                Err(_) => break,
+            }
         }
+        Ok(n)
+    }
 
-        let addr = (&mut self.memory[a]) as *mut u8 as *mut T;
+    /// Starts a cursor-based read sequence at `addr`; see `MemoryReader`.
+    pub fn reader(&self, addr: PhysAddr) -> MemoryReader<'_> {
+        MemoryReader {
+            memory: self,
+            cursor: addr,
+        }
+    }
+
+    /// Starts a cursor-based write sequence at `addr`; see `MemoryWriter`.
+    pub fn writer(&mut self, addr: PhysAddr) -> MemoryWriter<'_> {
+        MemoryWriter {
+            memory: self,
+            cursor: addr,
+        }
+    }
+}
+
+/// A one-directional cursor over `Memory` for sequentially reading typed
+/// values, advancing past each one as it's consumed. Prevents double-fetch
+/// bugs: a later `read::<T>()` can't re-observe bytes an earlier one already
+/// moved past (mirrors the uaccess pattern kernels use on untrusted structs).
+pub struct MemoryReader<'a> {
+    memory: &'a Memory,
+    cursor: PhysAddr,
+}
+
+impl<'a> MemoryReader<'a> {
+    /// Reads a `T` at the cursor and advances it by `size_of::<T>()`.
+    pub fn read<T: Copy>(&mut self) -> Result<T, MemoryError> {
+        let val = self.memory.try_read(self.cursor)?;
+        self.cursor = PhysAddr::from_bits(self.cursor.bits() + core::mem::size_of::<T>() as u64);
+        Ok(val)
+    }
+
+    /// The address the next `read` will start from.
+    pub fn position(&self) -> PhysAddr {
+        self.cursor
+    }
+}
+
+/// Write-side counterpart to `MemoryReader`; see its docs.
+pub struct MemoryWriter<'a> {
+    memory: &'a mut Memory,
+    cursor: PhysAddr,
+}
+
+impl<'a> MemoryWriter<'a> {
+    /// Writes `val` at the cursor and advances it by `size_of::<T>()`.
+    pub fn write<T>(&mut self, val: T) -> Result<(), MemoryError> {
+        self.memory.try_write(self.cursor, val)?;
+        self.cursor = PhysAddr::from_bits(self.cursor.bits() + core::mem::size_of::<T>() as u64);
+        Ok(())
+    }
 
-        unsafe { addr.as_mut().unwrap() }
+    /// The address the next `write` will start from.
+    pub fn position(&self) -> PhysAddr {
+        self.cursor
     }
 }
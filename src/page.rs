@@ -1,5 +1,81 @@
-use std::ops::{Deref, DerefMut};
-use crate::addr::PhysAddr;
+use core::ops::BitOr;
+use crate::addr::{PhysAddr, VirtAddr};
+
+/// RISC-V leaf/non-leaf PTE bits (V, R, W, X, U, G, A, D), as a bitflags-style wrapper.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct PteFlags(u64);
+
+impl PteFlags {
+    pub const VALID: Self = Self(1 << 0);
+    pub const READ: Self = Self(1 << 1);
+    pub const WRITE: Self = Self(1 << 2);
+    pub const EXEC: Self = Self(1 << 3);
+    pub const USER: Self = Self(1 << 4);
+    pub const GLOBAL: Self = Self(1 << 5);
+    pub const ACCESSED: Self = Self(1 << 6);
+    pub const DIRTY: Self = Self(1 << 7);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+    pub const fn contains(self, flag: Self) -> bool {
+        self.0 & flag.0 == flag.0
+    }
+}
+
+impl BitOr for PteFlags {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Kind of access being performed, used to pick the right permission bit during a walk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Load,
+    Store,
+    Fetch,
+}
+
+/// CPU privilege level a translation is performed on behalf of.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PrivilegeLevel {
+    User,
+    Supervisor,
+}
+
+/// Why a walk rejected a `PageTableEntry` at the level it inspected it.
+#[derive(Copy, Clone, Debug)]
+pub enum FaultCause {
+    /// The V bit is clear.
+    NotValid,
+    /// A non-leaf entry was found at the last level of the walk.
+    NotALeaf,
+    /// W set without R, which is a reserved encoding.
+    ReservedWriteOnly,
+    /// The leaf exists but doesn't grant the requested access kind.
+    PermissionDenied,
+    /// A user-mode access hit a supervisor-only mapping.
+    PrivilegeViolation,
+    /// A superpage leaf's frame has nonzero bits where it must be aligned.
+    MisalignedSuperpage,
+    /// The walk stepped outside of mapped physical memory.
+    OutOfBounds,
+}
+
+/// A failed translation: the address, the level it failed at (1 = root), and why.
+#[derive(Copy, Clone, Debug)]
+pub struct Fault {
+    pub addr: VirtAddr,
+    pub level: usize,
+    pub cause: FaultCause,
+}
+
+const PHYS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
 
 #[derive(Copy, Clone)]
 pub struct PageTableEntry {
@@ -7,37 +83,116 @@ pub struct PageTableEntry {
 }
 
 impl PageTableEntry {
-    pub const fn new_present(target: PhysAddr) -> Self {
+    /// A present mapping with `flags` (the VALID bit is set automatically;
+    /// `flags` should not include it).
+    pub const fn new_present_with_flags(target: PhysAddr, flags: PteFlags) -> Self {
         Self {
-            bits: target.bits() & 0x000FFFFFFFFFFFF000 | 1,
+            bits: (target.bits() & PHYS_MASK) | PteFlags::VALID.bits() | flags.bits(),
         }
     }
 
+    /// A present mapping, fully permissive (R/W/X/U).
+    pub const fn new_present(target: PhysAddr) -> Self {
+        Self::new_present_with_flags(
+            target,
+            PteFlags(
+                PteFlags::READ.bits()
+                    | PteFlags::WRITE.bits()
+                    | PteFlags::EXEC.bits()
+                    | PteFlags::USER.bits(),
+            ),
+        )
+    }
+
     pub const fn new_unmapped() -> Self {
         Self { bits: 0 }
     }
 
+    pub const fn flags(self) -> PteFlags {
+        PteFlags(self.bits & 0xFF)
+    }
+
     pub const fn is_present(self) -> bool {
-        self.bits & 1 > 0
+        self.flags().contains(PteFlags::VALID)
+    }
+
+    /// A leaf entry maps a page directly, rather than pointing at the next level table.
+    pub const fn is_leaf(self) -> bool {
+        let f = self.flags();
+        f.contains(PteFlags::READ) || f.contains(PteFlags::WRITE) || f.contains(PteFlags::EXEC)
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.flags().contains(PteFlags::READ)
+    }
+    pub const fn is_writable(self) -> bool {
+        self.flags().contains(PteFlags::WRITE)
+    }
+    pub const fn is_executable(self) -> bool {
+        self.flags().contains(PteFlags::EXEC)
+    }
+    pub const fn is_user(self) -> bool {
+        self.flags().contains(PteFlags::USER)
+    }
+    pub const fn is_global(self) -> bool {
+        self.flags().contains(PteFlags::GLOBAL)
+    }
+    pub const fn is_accessed(self) -> bool {
+        self.flags().contains(PteFlags::ACCESSED)
+    }
+    pub const fn is_dirty(self) -> bool {
+        self.flags().contains(PteFlags::DIRTY)
+    }
+
+    pub fn set_accessed(&mut self) {
+        self.bits |= PteFlags::ACCESSED.bits();
+    }
+    pub fn set_dirty(&mut self) {
+        self.bits |= PteFlags::DIRTY.bits();
     }
 
     pub const fn phys_addr(self) -> PhysAddr {
-        PhysAddr::from_bits(self.bits & 0x000FFFFFFFFFFFF000)
+        PhysAddr::from_bits(self.bits & PHYS_MASK)
     }
-}
 
-#[derive(Copy, Clone)]
-pub struct PageTable {
-    table: [PageTableEntry; 512],
-}
-impl Deref for PageTable {
-    type Target = [PageTableEntry; 512];
-    fn deref(&self) -> &Self::Target {
-        &self.table
+    /// Physical address of the `index`-th entry in a table located at `table`.
+    /// Entries are addressed individually (rather than as one `[PTE; N]`-sized
+    /// struct) since `N` is mode-dependent (1024 for Sv32, 512 otherwise).
+    pub const fn addr_in_table(table: PhysAddr, index: usize) -> PhysAddr {
+        PhysAddr::from_bits(table.bits() + (index * core::mem::size_of::<Self>()) as u64)
     }
-}
-impl DerefMut for PageTable {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.table
+
+    /// Classifies why this entry cannot satisfy `access` at `privilege`, or
+    /// `None` if the walk may proceed/complete.
+    pub fn check_access(
+        self,
+        access: AccessKind,
+        privilege: PrivilegeLevel,
+        is_last_level: bool,
+    ) -> Option<FaultCause> {
+        if !self.is_present() {
+            return Some(FaultCause::NotValid);
+        }
+        if !is_last_level {
+            return None;
+        }
+        if !self.is_leaf() {
+            return Some(FaultCause::NotALeaf);
+        }
+        if self.is_writable() && !self.is_readable() {
+            return Some(FaultCause::ReservedWriteOnly);
+        }
+        if !self.is_user() && privilege == PrivilegeLevel::User {
+            return Some(FaultCause::PrivilegeViolation);
+        }
+        let granted = match access {
+            AccessKind::Load => self.is_readable(),
+            AccessKind::Store => self.is_writable(),
+            AccessKind::Fetch => self.is_executable(),
+        };
+        if !granted {
+            return Some(FaultCause::PermissionDenied);
+        }
+        None
     }
 }
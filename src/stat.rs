@@ -1,10 +1,36 @@
+//! Plain counters, no `std` or `alloc` dependency: safe to use from a
+//! `no_std` embedder as well as the host-side simulator.
+
 pub struct CacheStats {
     pub hit: usize,
     pub miss: usize,
+    /// Of `miss`, how many found no entry for the tag at all.
+    pub miss_cold: usize,
+    /// Of `miss`, how many found a same-tag entry tagged with a different,
+    /// non-global ASID (a context-switch conflict rather than a cold miss).
+    pub miss_asid_conflict: usize,
+    /// Writes that hit an already-cached line. Unused outside the L1 cache.
+    pub write_hit: usize,
+    /// Writes that missed and had to allocate a line. Unused outside the L1 cache.
+    pub write_miss: usize,
+    /// Dirty lines flushed back to memory on eviction. Unused outside the L1 cache.
+    pub write_back: usize,
+    /// Valid entries replaced to make room for a new one, under whichever
+    /// `ReplacementPolicy` this structure is using.
+    pub evictions: usize,
 }
 impl CacheStats {
     fn new() -> Self {
-        Self { hit: 0, miss: 0 }
+        Self {
+            hit: 0,
+            miss: 0,
+            miss_cold: 0,
+            miss_asid_conflict: 0,
+            write_hit: 0,
+            write_miss: 0,
+            write_back: 0,
+            evictions: 0,
+        }
     }
     pub fn hit(&mut self) {
         self.hit += 1;
@@ -12,6 +38,30 @@ impl CacheStats {
     pub fn miss(&mut self) {
         self.miss += 1;
     }
+    /// Records a miss where no entry for the tag existed at all.
+    pub fn miss_cold(&mut self) {
+        self.miss += 1;
+        self.miss_cold += 1;
+    }
+    /// Records a miss where an entry for the tag existed but under a
+    /// different, non-global ASID.
+    pub fn miss_asid_conflict(&mut self) {
+        self.miss += 1;
+        self.miss_asid_conflict += 1;
+    }
+    pub fn write_hit(&mut self) {
+        self.write_hit += 1;
+    }
+    pub fn write_miss(&mut self) {
+        self.write_miss += 1;
+    }
+    pub fn write_back(&mut self) {
+        self.write_back += 1;
+    }
+    /// Records a valid entry being replaced to make room for a new one.
+    pub fn evict(&mut self) {
+        self.evictions += 1;
+    }
 }
 
 pub struct Stats {